@@ -1,4 +1,8 @@
 //! Configuration related types and utilities
+//!
+//! [Config::load] assembles the effective configuration from, in
+//! increasing precedence: an optional base file (TOML or JSON), then
+//! `MONGEU_`-prefixed environment variables, then explicit CLI flags.
 use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::time::Duration;
@@ -23,6 +27,11 @@ const DEFAULT_GC_MIN_CAMPAIGNS: NonZeroUsize = unsafe { NonZeroUsize::new_unchec
 
 const DEFAULT_CACHE_MAX_AGE: Duration = Duration::from_secs(15 * 60);
 
+const DEFAULT_MONITOR_ENABLE: bool = false;
+const DEFAULT_MONITOR_PERIOD: Duration = Duration::from_secs(1);
+
+const DEFAULT_PERSISTENCE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
 /// General configuration
 #[derive(Default, Deserialize)]
 #[serde(default)]
@@ -31,24 +40,80 @@ pub struct Config {
     pub oneshot: Oneshot,
     pub gc: GC,
     pub misc: Misc,
+    pub monitor: Monitor,
+    pub hooks: Hooks,
+    pub access: Access,
+    pub persistence: Persistence,
 }
 
 impl Config {
-    /// Retrieve a [Config] from a TOML file
-    pub fn from_toml_file(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+    /// Assemble a [Config] from three layers, in increasing precedence:
+    /// an optional base file (TOML or JSON, picked by its extension),
+    /// `MONGEU_`-prefixed environment variables, and finally explicit CLI
+    /// flags, parsed from `matches`.
+    pub fn load(
+        file: Option<&std::path::Path>,
+        matches: &clap::ArgMatches,
+    ) -> anyhow::Result<Self> {
         use anyhow::Context;
-
-        let toml = std::fs::read_to_string(path.as_ref())
-            .with_context(|| format!("Could not read file {}", path.as_ref().display()))?;
-        Self::from_toml(toml)
+        use clap::FromArgMatches;
+        use figment::providers::{Env, Format, Json, Toml};
+        use figment::Figment;
+
+        let mut figment = Figment::new();
+        if let Some(path) = file {
+            figment = match path.extension().and_then(std::ffi::OsStr::to_str) {
+                Some("json") => figment.merge(Json::file(path).required(true)),
+                Some("toml") | None => figment.merge(Toml::file(path).required(true)),
+                Some(ext) => anyhow::bail!(
+                    "Unsupported configuration file extension '{ext}' of file {}",
+                    path.display()
+                ),
+            };
+        }
+        figment = figment.merge(Env::prefixed("MONGEU_").map(|k| env_key_to_path(k.as_str())));
+
+        let mut config: Self = figment.extract().with_context(|| {
+            format!(
+                "Could not assemble configuration from {} and the 'MONGEU_' environment",
+                file.map_or("defaults".to_string(), |p| format!("file {}", p.display()))
+            )
+        })?;
+        config
+            .update_from_arg_matches(matches)
+            .context("Could not overlay configuration from CLI flags")?;
+        Ok(config)
     }
+}
 
-    /// Retrieve a [Config] from a TOML, provided as [str]
-    pub fn from_toml(toml: impl AsRef<str>) -> anyhow::Result<Self> {
-        use anyhow::Context;
+/// Sections nested directly under [Config], in the order their fields are
+/// declared. Used to turn a flat `MONGEU_`-stripped environment variable
+/// name into the dotted path `figment` expects, e.g. `GC_MIN_AGE` becomes
+/// `gc.min_age`; a name matching no known section is only lowercased, and
+/// as [Config] does not deny unknown fields, such a key is ignored.
+const ENV_SECTIONS: &[&str] = &[
+    "network",
+    "oneshot",
+    "gc",
+    "misc",
+    "monitor",
+    "hooks",
+    "access",
+    "persistence",
+];
 
-        toml::from_str(toml.as_ref()).context("Could not parse TOML")
-    }
+/// Turn a `MONGEU_`-stripped environment variable name into the dotted path
+/// `figment` expects, e.g. `GC_MIN_AGE` becomes `gc.min_age`
+fn env_key_to_path(key: &str) -> String {
+    let lower = key.to_lowercase();
+    ENV_SECTIONS
+        .iter()
+        .find_map(|section| {
+            lower
+                .strip_prefix(&format!("{section}_"))
+                .map(|rest| format!("{section}.{rest}"))
+        })
+        .unwrap_or(lower)
 }
 
 impl Args for Config {
@@ -61,6 +126,10 @@ impl Args for Config {
         let cmd = Oneshot::augment_args_for_update(cmd);
         let cmd = GC::augment_args_for_update(cmd);
         let cmd = Misc::augment_args_for_update(cmd);
+        let cmd = Monitor::augment_args_for_update(cmd);
+        let cmd = Hooks::augment_args_for_update(cmd);
+        let cmd = Access::augment_args_for_update(cmd);
+        let cmd = Persistence::augment_args_for_update(cmd);
         cmd
     }
 }
@@ -80,6 +149,10 @@ impl clap::FromArgMatches for Config {
         self.oneshot.update_from_arg_matches(matches)?;
         self.gc.update_from_arg_matches(matches)?;
         self.misc.update_from_arg_matches(matches)?;
+        self.monitor.update_from_arg_matches(matches)?;
+        self.hooks.update_from_arg_matches(matches)?;
+        self.access.update_from_arg_matches(matches)?;
+        self.persistence.update_from_arg_matches(matches)?;
         Ok(())
     }
 }
@@ -142,6 +215,74 @@ impl std::str::FromStr for ListenAddr {
     }
 }
 
+/// A CIDR block, e.g. `10.0.0.0/8`; a bare address is treated as a
+/// single-address (`/32` or `/128`) block
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub struct CidrBlock {
+    /// Network address of this block
+    ip: IpAddr,
+    /// Prefix length in bits; defaults to a full-length, single-address block
+    #[serde(default)]
+    prefix_len: Option<u8>,
+}
+
+impl CidrBlock {
+    /// Prefix length to actually use, defaulting to a full-length block
+    fn effective_prefix_len(&self) -> u32 {
+        self.prefix_len
+            .unwrap_or(if self.ip.is_ipv4() { 32 } else { 128 })
+            .into()
+    }
+
+    /// Whether `addr` falls within this block
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.ip, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let len = self.effective_prefix_len().min(32);
+                let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let len = self.effective_prefix_len().min(128);
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for CidrBlock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use anyhow::Context;
+
+        match s.split_once('/') {
+            Some((ip, len)) => {
+                let ip: IpAddr = ip.parse().context("Invalid address in CIDR block")?;
+                let prefix_len: u8 = len.parse().context("Invalid prefix length in CIDR block")?;
+                let max_len = if ip.is_ipv4() { 32 } else { 128 };
+                anyhow::ensure!(
+                    prefix_len <= max_len,
+                    "Prefix length {prefix_len} exceeds {max_len} bits for {ip}"
+                );
+                Ok(Self {
+                    ip,
+                    prefix_len: Some(prefix_len),
+                })
+            }
+            None => {
+                let ip: IpAddr = s.parse().context("Invalid address in CIDR block")?;
+                Ok(Self {
+                    ip,
+                    prefix_len: None,
+                })
+            }
+        }
+    }
+}
+
 /// Oneshot measurement configuration
 #[derive(Copy, Clone, Args, Deserialize)]
 #[serde(default)]
@@ -212,6 +353,99 @@ impl Default for Misc {
     }
 }
 
+/// Background monitoring configuration
+#[derive(Copy, Clone, Args, Deserialize)]
+#[serde(default)]
+pub struct Monitor {
+    /// Enable the background sampler and the `/metrics` end-point
+    #[arg(long = "enable-monitor")]
+    pub enable: bool,
+
+    /// Sampling period of the background monitor
+    #[arg(long = "monitor-period", value_name("MILLIS"), value_parser = util::parse_millis)]
+    #[serde(deserialize_with = "util::deserialize_millis")]
+    pub period: Duration,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            enable: DEFAULT_MONITOR_ENABLE,
+            period: DEFAULT_MONITOR_PERIOD,
+        }
+    }
+}
+
+/// Event hook configuration: external programs invoked on campaign and
+/// garbage-collection lifecycle events, plus the background monitor's
+/// power threshold
+#[derive(Clone, Default, Args, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    /// Program to run when a measurement campaign is created
+    #[arg(long = "hook-campaign-created", value_name("PROGRAM"))]
+    pub campaign_created: Option<std::path::PathBuf>,
+
+    /// Program to run when a measurement campaign is explicitly deleted
+    #[arg(long = "hook-campaign-deleted", value_name("PROGRAM"))]
+    pub campaign_deleted: Option<std::path::PathBuf>,
+
+    /// Program to run when the garbage collector reaps campaigns
+    #[arg(long = "hook-campaigns-reaped", value_name("PROGRAM"))]
+    pub campaigns_reaped: Option<std::path::PathBuf>,
+
+    /// Program to run when the background monitor observes a device
+    /// crossing `power_threshold_mw`
+    #[arg(long = "hook-threshold-crossed", value_name("PROGRAM"))]
+    pub threshold_crossed: Option<std::path::PathBuf>,
+
+    /// Power draw, in `mW`, at which `threshold_crossed` is triggered; the
+    /// hook is never triggered if unset
+    #[arg(long = "hook-power-threshold", value_name("MILLIWATTS"))]
+    pub power_threshold_mw: Option<u64>,
+}
+
+/// IP allowlist access-control configuration
+#[derive(Clone, Default, Args, Deserialize)]
+#[serde(default)]
+pub struct Access {
+    /// CIDR ranges permitted to access the API; if empty, every client is permitted
+    #[arg(long = "allow", value_name("CIDR"))]
+    pub allowed: Vec<CidrBlock>,
+
+    /// Addresses of reverse proxies trusted to set `X-Forwarded-For`
+    #[arg(long = "trusted-proxy", value_name("IP"))]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Campaign persistence configuration
+#[derive(Clone, Args, Deserialize)]
+#[serde(default)]
+pub struct Persistence {
+    /// File to persist active campaigns to, and restore them from at
+    /// startup; persistence is disabled if unset
+    #[arg(long = "persistence-file", value_name("FILE"))]
+    pub file: Option<std::path::PathBuf>,
+
+    /// Interval between periodic persistence snapshots
+    #[arg(
+        long = "persistence-period",
+        value_name("SECONDS"),
+        value_parser = util::parse_secs
+    )]
+    #[serde(deserialize_with = "util::deserialize_secs")]
+    pub period: Duration,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            file: None,
+            period: DEFAULT_PERSISTENCE_PERIOD,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -257,6 +491,10 @@ mod tests {
             oneshot,
             gc,
             misc,
+            monitor: _,
+            hooks: _,
+            access: _,
+            persistence: _,
         } = toml::from_str(include_str!("../example_config.toml")).expect("Could not parse TOML");
 
         assert_eq!(network.port, 80);
@@ -277,4 +515,36 @@ mod tests {
         assert_eq!(misc.base_uri.query(), None);
         assert_eq!(misc.base_uri.path(), "/");
     }
+
+    #[test]
+    fn env_key_to_path_maps_known_sections() {
+        assert_eq!(env_key_to_path("NETWORK_PORT"), "network.port");
+        assert_eq!(env_key_to_path("GC_MIN_AGE"), "gc.min_age");
+        assert_eq!(env_key_to_path("MISC_CACHE_MAX_AGE"), "misc.cache_max_age");
+    }
+
+    #[test]
+    fn env_key_to_path_passes_through_unknown_sections() {
+        assert_eq!(env_key_to_path("BOGUS_VALUE"), "bogus_value");
+    }
+
+    #[test]
+    fn cidr_block_contains_matches_prefix() {
+        let block: CidrBlock = "10.0.0.0/8".parse().expect("Could not parse CIDR block");
+        assert!(block.contains(IpAddr::from([10, 1, 2, 3])));
+        assert!(!block.contains(IpAddr::from([11, 0, 0, 1])));
+    }
+
+    #[test]
+    fn cidr_block_bare_address_is_single_host() {
+        let block: CidrBlock = "192.168.1.1".parse().expect("Could not parse CIDR block");
+        assert!(block.contains(IpAddr::from([192, 168, 1, 1])));
+        assert!(!block.contains(IpAddr::from([192, 168, 1, 2])));
+    }
+
+    #[test]
+    fn cidr_block_rejects_mismatched_family() {
+        let block: CidrBlock = "10.0.0.0/8".parse().expect("Could not parse CIDR block");
+        assert!(!block.contains(Ipv6Addr::LOCALHOST.into()));
+    }
 }
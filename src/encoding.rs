@@ -0,0 +1,130 @@
+//! `Accept`-header-driven content negotiation for response bodies
+use anyhow::Context;
+use warp::http::{header, StatusCode};
+use warp::reply::{self, Reply};
+
+use crate::replyify::Replyify;
+
+/// A media type a response body can be encoded as
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Json,
+    Yaml,
+    Csv,
+}
+
+impl MediaType {
+    /// `Content-Type` to report for this [MediaType]
+    const fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Yaml => "application/yaml",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    /// Parse a single media-type essence (no parameters), e.g. `"application/json"`
+    fn from_essence(essence: &str) -> Option<Self> {
+        match essence {
+            "application/json" => Some(Self::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(Self::Yaml),
+            "text/csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Values that can additionally be rendered as CSV. [encode] requires this
+/// of every value; as [ToCsv::to_csv] defaults to `None`, an empty impl is
+/// enough for a value that is only encoded as JSON or YAML
+pub trait ToCsv {
+    /// Render as CSV text, or `None` if this value has no sensible tabular
+    /// shape
+    fn to_csv(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Negotiate a [MediaType] from a (possibly absent) `Accept` header value,
+/// honoring `q`-values, and defaulting to JSON when no header is present
+/// or the client accepts `*/*`. Returns `None` when the client demands a
+/// media type we do not support.
+pub fn negotiate(accept: Option<&str>) -> Option<MediaType> {
+    let Some(accept) = accept else {
+        return Some(MediaType::Json);
+    };
+
+    let mut candidates: Vec<(f32, &str)> = accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let essence = parts.next()?.trim();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|v| v.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((q, essence))
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    candidates.into_iter().find_map(|(_, essence)| {
+        MediaType::from_essence(essence).or((essence == "*/*").then_some(MediaType::Json))
+    })
+}
+
+/// Serialize `value` as `media_type`, returning a response with the
+/// matching `Content-Type`. Responds `406 Not Acceptable` when
+/// `media_type` is `None` or CSV was negotiated for a `value` that has no
+/// tabular shape.
+pub fn encode<T>(value: &T, media_type: Option<MediaType>) -> reply::Response
+where
+    T: serde::Serialize + ToCsv,
+{
+    let Some(media_type) = media_type else {
+        return reply::with_status("Not Acceptable", StatusCode::NOT_ACCEPTABLE).into_response();
+    };
+
+    let body = match media_type {
+        MediaType::Json => serde_json::to_string(value).context("Could not serialize as JSON"),
+        MediaType::Yaml => serde_yaml::to_string(value).context("Could not serialize as YAML"),
+        MediaType::Csv => value
+            .to_csv()
+            .ok_or_else(|| anyhow::anyhow!("This value cannot be rendered as CSV")),
+    };
+
+    match body {
+        Ok(body) => reply::with_header(body, header::CONTENT_TYPE, media_type.content_type()).into_response(),
+        Err(e) if media_type == MediaType::Csv => {
+            log::trace!("Encountered error: {e:#}");
+            reply::with_status(e.to_string(), StatusCode::NOT_ACCEPTABLE).into_response()
+        }
+        Err(e) => Replyify::replyify(e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_defaults_to_json_without_header() {
+        assert_eq!(negotiate(None), Some(MediaType::Json));
+    }
+
+    #[test]
+    fn negotiate_picks_highest_q_value() {
+        let accept = "text/csv;q=0.2, application/yaml;q=0.8, application/json;q=0.5";
+        assert_eq!(negotiate(Some(accept)), Some(MediaType::Yaml));
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_json_on_wildcard() {
+        assert_eq!(negotiate(Some("*/*")), Some(MediaType::Json));
+    }
+
+    #[test]
+    fn negotiate_rejects_unsupported_media_types() {
+        assert_eq!(negotiate(Some("application/xml")), None);
+    }
+}
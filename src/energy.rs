@@ -1,5 +1,5 @@
 //! Energy consumption measurement and associated utilities
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use nvml_wrapper as nvml;
@@ -9,28 +9,28 @@ use crate::util;
 /// Store for measurment campaigns
 #[derive(Default, Debug)]
 pub struct BaseMeasurements {
-    next_id: BMId,
     campaigns: std::collections::HashMap<BMId, BaseMeasurement>,
 }
 
 impl BaseMeasurements {
-    /// Create a new [BaseMeasurement]
+    /// Create a new [BaseMeasurement] under a freshly generated [BMId]
     pub fn create(&mut self, nvml: &'static nvml::Nvml) -> anyhow::Result<BMId> {
         use std::collections::hash_map::Entry;
 
-        let id = self.next_id;
-        if let Entry::Vacant(entry) = self.campaigns.entry(id) {
-            entry.insert(
-                BaseMeasurement::new(nvml).context("Could not create a new base measurement")?,
-            );
-
-            // We choose new indexes by simple incrementation. Thus, one
-            // can easily guess ids of past base measurements after
-            // creating a new one.
-            self.next_id = id.wrapping_add(1);
-            Ok(id)
-        } else {
-            Err(anyhow::anyhow!("Targeted id {id} already taken"))
+        loop {
+            let id = BMId::generate();
+            match self.campaigns.entry(id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(
+                        BaseMeasurement::new(nvml)
+                            .context("Could not create a new base measurement")?,
+                    );
+                    return Ok(id);
+                }
+                // Drawing the same 128-bit id twice is astronomically
+                // unlikely; if it somehow happens, just draw another one.
+                Entry::Occupied(_) => log::warn!("Generated a colliding campaign id {id}, retrying"),
+            }
         }
     }
 
@@ -39,9 +39,12 @@ impl BaseMeasurements {
         self.campaigns.remove(&id)
     }
 
-    /// Delete [BaseMeasurement]s older than the given `instant`
-    pub fn delete_older_than(&mut self, instant: Instant) {
-        self.campaigns.retain(|_, b| b.time < instant)
+    /// Delete [BaseMeasurement]s older than the given `instant`, returning
+    /// the number of campaigns that were removed
+    pub fn delete_older_than(&mut self, instant: Instant) -> usize {
+        let before = self.campaigns.len();
+        self.campaigns.retain(|_, b| b.time >= instant);
+        before - self.campaigns.len()
     }
 
     /// Retrieve the [BaseMeasurement] with the given id
@@ -53,10 +56,83 @@ impl BaseMeasurements {
     pub fn len(&self) -> usize {
         self.campaigns.len()
     }
+
+    /// Iterate over all held campaigns, for persistence
+    pub fn iter(&self) -> impl Iterator<Item = (BMId, &BaseMeasurement)> {
+        self.campaigns.iter().map(|(&id, b)| (id, b))
+    }
+
+    /// Insert a campaign restored from persisted state under its original
+    /// id, unless that id is already occupied
+    pub fn restore(&mut self, id: BMId, measurement: BaseMeasurement) {
+        self.campaigns.entry(id).or_insert(measurement);
+    }
+}
+
+/// Opaque identifier for a [BaseMeasurement] in a [BaseMeasurements]
+///
+/// Backed by 128 bits drawn from a CSPRNG rather than a counter, so past
+/// (or future) campaign ids cannot be guessed from a known one. Renders
+/// as URL-safe, unpadded base64 so it can be used as-is in a path segment
+/// such as `/v1/energy/{id}` and parsed back out of one.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BMId([u8; 16]);
+
+impl BMId {
+    /// Generate a new random [BMId]
+    fn generate() -> Self {
+        Self(rand::random())
+    }
 }
 
-/// Identifier for [BaseMeasurement] in a [BaseMeasurements]
-pub type BMId = u32;
+impl std::fmt::Display for BMId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use base64::Engine;
+
+        f.write_str(&base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.0))
+    }
+}
+
+impl std::str::FromStr for BMId {
+    type Err = BMIdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use base64::Engine;
+
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(s)
+            .map_err(|_| BMIdParseError)?;
+        bytes.try_into().map(Self).map_err(|_| BMIdParseError)
+    }
+}
+
+impl serde::Serialize for BMId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BMId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Error parsing a [BMId] from its string representation
+#[derive(Copy, Clone, Debug)]
+pub struct BMIdParseError;
+
+impl std::fmt::Display for BMIdParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Invalid campaign id")
+    }
+}
+
+impl std::error::Error for BMIdParseError {}
 
 /// A base measurement across multiple devices
 #[derive(Debug)]
@@ -92,6 +168,73 @@ impl BaseMeasurement {
             .collect::<Result<_, _>>()?;
         Ok(Measurement { duration, devices })
     }
+
+    /// Capture this [BaseMeasurement] as a [PersistedMeasurement], for
+    /// persistence across restarts
+    pub fn persist(&self) -> PersistedMeasurement {
+        let created_at = SystemTime::now()
+            .checked_sub(self.time.elapsed())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let devices = self
+            .devices
+            .iter()
+            .filter_map(|d| d.device.index().ok().map(|id| (id, d.energy)))
+            .collect();
+        PersistedMeasurement {
+            created_at_secs: created_at.as_secs(),
+            devices,
+        }
+    }
+
+    /// Restore a [BaseMeasurement] from a [PersistedMeasurement], re-opening
+    /// each device by its stored index. Returns `Ok(None)` instead of
+    /// restoring the campaign if any device's current energy counter has
+    /// fallen below its stored baseline, which indicates the NVML driver
+    /// was reloaded (and the baseline is no longer meaningful) since the
+    /// state was persisted.
+    pub fn restore(
+        nvml: &'static nvml::Nvml,
+        persisted: &PersistedMeasurement,
+    ) -> anyhow::Result<Option<Self>> {
+        let created_at = UNIX_EPOCH + std::time::Duration::from_secs(persisted.created_at_secs);
+        let elapsed = SystemTime::now().duration_since(created_at).unwrap_or_default();
+        let time = Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(Instant::now);
+
+        let mut devices = Vec::with_capacity(persisted.devices.len());
+        for &(index, baseline) in &persisted.devices {
+            let device = nvml
+                .device_by_index(index)
+                .with_context(|| format!("Could not retrieve device {index}"))?;
+            let current = device
+                .total_energy_consumption()
+                .with_context(|| format!("Could not retrieve total energy consumption of device {index}"))?;
+            if current < baseline {
+                log::warn!(
+                    "Device {index}'s energy counter is behind its persisted baseline, \
+                     assuming a driver reset and evicting the campaign"
+                );
+                return Ok(None);
+            }
+            devices.push(BaseDeviceData {
+                device,
+                energy: baseline,
+            });
+        }
+        Ok(Some(Self { time, devices }))
+    }
+}
+
+/// Serializable snapshot of a [BaseMeasurement], for persistence across
+/// restarts
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PersistedMeasurement {
+    /// Wall-clock creation time, as seconds since the Unix epoch
+    created_at_secs: u64,
+    /// Per-device baseline energy counters in `mJ`, keyed by device index
+    devices: Vec<(u32, u64)>,
 }
 
 /// Total energy consumption of a specific device
@@ -140,6 +283,19 @@ pub struct Measurement {
     devices: Vec<DeviceData>,
 }
 
+impl crate::encoding::ToCsv for Measurement {
+    fn to_csv(&self) -> Option<String> {
+        use std::fmt::Write;
+
+        let duration_ms = self.duration.as_millis();
+        let mut out = String::from("duration_ms,device_id,energy_mj\n");
+        for d in &self.devices {
+            writeln!(out, "{duration_ms},{},{}", d.id, d.energy).ok()?;
+        }
+        Some(out)
+    }
+}
+
 /// Data associated with a specific device
 #[derive(Copy, Clone, Debug, serde::Serialize)]
 pub struct DeviceData {
@@ -182,3 +338,46 @@ fn total_energy_consumption(device: nvml::Device, id: u32) -> Result<u64> {
         .total_energy_consumption()
         .with_context(|| format!("Could not retrieve total energy consumption of device {id}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bmid_roundtrips_through_display_and_fromstr() {
+        let id = BMId::generate();
+        let parsed: BMId = id.to_string().parse().expect("Could not parse BMId");
+        assert_eq!(id, parsed);
+    }
+
+    #[test]
+    fn bmid_generate_is_unguessable_in_practice() {
+        assert_ne!(BMId::generate(), BMId::generate());
+    }
+
+    #[test]
+    fn bmid_rejects_garbage() {
+        assert!("not valid base64!!".parse::<BMId>().is_err());
+    }
+
+    #[test]
+    fn bmid_roundtrips_through_serde() {
+        let id = BMId::generate();
+        let json = serde_json::to_string(&id).expect("Could not serialize BMId");
+        let parsed: BMId = serde_json::from_str(&json).expect("Could not deserialize BMId");
+        assert_eq!(id, parsed);
+    }
+
+    #[test]
+    fn persisted_measurement_roundtrips_through_serde() {
+        let persisted = PersistedMeasurement {
+            created_at_secs: 1_700_000_000,
+            devices: vec![(0, 42), (1, 1337)],
+        };
+        let toml = toml::to_string(&persisted).expect("Could not serialize PersistedMeasurement");
+        let parsed: PersistedMeasurement =
+            toml::from_str(&toml).expect("Could not deserialize PersistedMeasurement");
+        assert_eq!(persisted.created_at_secs, parsed.created_at_secs);
+        assert_eq!(persisted.devices, parsed.devices);
+    }
+}
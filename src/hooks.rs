@@ -0,0 +1,134 @@
+//! Event hooks: user-configured external commands invoked on campaign and
+//! garbage-collection lifecycle events
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::process::Command;
+
+use crate::energy::BMId;
+
+/// A lifecycle event a hook may be configured to run for
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A new measurement campaign was created
+    CampaignCreated { id: BMId, device_count: u32 },
+    /// A measurement campaign was explicitly deleted
+    CampaignDeleted { id: BMId, device_count: u32 },
+    /// The garbage collector reaped one or more campaigns
+    CampaignsReaped { count: usize },
+    /// The background monitor observed a device crossing a power threshold
+    ThresholdCrossed { device: u32, value_mw: u64 },
+}
+
+impl Event {
+    /// Short, stable name for this event, passed as `MONGEU_EVENT`
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::CampaignCreated { .. } => "campaign_created",
+            Self::CampaignDeleted { .. } => "campaign_deleted",
+            Self::CampaignsReaped { .. } => "campaigns_reaped",
+            Self::ThresholdCrossed { .. } => "threshold_crossed",
+        }
+    }
+
+    /// Environment variables describing this event
+    fn envs(&self) -> Vec<(&'static str, String)> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut envs = vec![
+            ("MONGEU_EVENT", self.kind().to_string()),
+            ("MONGEU_TIMESTAMP", timestamp.to_string()),
+        ];
+        match self {
+            Self::CampaignCreated { id, device_count } | Self::CampaignDeleted { id, device_count } => {
+                envs.push(("MONGEU_CAMPAIGN_ID", id.to_string()));
+                envs.push(("MONGEU_DEVICE_COUNT", device_count.to_string()));
+            }
+            Self::CampaignsReaped { count } => envs.push(("MONGEU_REAPED_COUNT", count.to_string())),
+            Self::ThresholdCrossed { device, value_mw } => {
+                envs.push(("MONGEU_DEVICE", device.to_string()));
+                envs.push(("MONGEU_VALUE_MW", value_mw.to_string()));
+            }
+        }
+        envs
+    }
+}
+
+/// Run `program` (if configured) for `event`, passing event details as
+/// environment variables. The hook is spawned and its completion is not
+/// awaited, so a slow or hanging hook never blocks the request that
+/// triggered it.
+pub fn fire(program: Option<&Path>, event: Event) {
+    let Some(program) = program else { return };
+    let program = program.to_path_buf();
+
+    tokio::spawn(async move {
+        let mut cmd = Command::new(&program);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .envs(event.envs());
+
+        match cmd.status().await {
+            Ok(status) if !status.success() => {
+                log::warn!(
+                    "Hook {} for event '{}' exited with {status}",
+                    program.display(),
+                    event.kind()
+                );
+            }
+            Err(e) => log::warn!("Could not run hook {}: {e}", program.display()),
+            Ok(_) => {}
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An arbitrary, fixed [BMId] for tests that don't care which one they get
+    fn test_id() -> BMId {
+        "AAAAAAAAAAAAAAAAAAAAAA"
+            .parse()
+            .expect("Could not parse a fixed test BMId")
+    }
+
+    #[test]
+    fn kind_is_stable_per_variant() {
+        assert_eq!(
+            Event::CampaignCreated { id: test_id(), device_count: 1 }.kind(),
+            "campaign_created"
+        );
+        assert_eq!(
+            Event::CampaignDeleted { id: test_id(), device_count: 1 }.kind(),
+            "campaign_deleted"
+        );
+        assert_eq!(Event::CampaignsReaped { count: 1 }.kind(), "campaigns_reaped");
+        assert_eq!(
+            Event::ThresholdCrossed { device: 0, value_mw: 1 }.kind(),
+            "threshold_crossed"
+        );
+    }
+
+    #[test]
+    fn envs_always_includes_the_event_kind_and_a_timestamp() {
+        let envs = Event::CampaignsReaped { count: 3 }.envs();
+
+        assert!(envs.contains(&("MONGEU_EVENT", "campaigns_reaped".to_string())));
+        assert!(envs.iter().any(|(k, _)| *k == "MONGEU_TIMESTAMP"));
+        assert!(envs.contains(&("MONGEU_REAPED_COUNT", "3".to_string())));
+    }
+
+    #[test]
+    fn envs_describes_threshold_crossed_details() {
+        let envs = Event::ThresholdCrossed { device: 2, value_mw: 12_345 }.envs();
+
+        assert!(envs.contains(&("MONGEU_DEVICE", "2".to_string())));
+        assert!(envs.contains(&("MONGEU_VALUE_MW", "12345".to_string())));
+    }
+}
@@ -3,20 +3,24 @@ use std::num::NonZeroUsize;
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use nvml_wrapper as nvml;
 
 use anyhow::Context;
 use log::LevelFilter;
 use nvml::error::NvmlError;
 use nvml::Nvml;
-use tokio::net::{TcpListener, TcpStream};
 use tokio::sync;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 mod config;
+mod encoding;
 mod energy;
 mod health;
+mod hooks;
+mod metrics;
 mod param;
+mod persistence;
 mod replyify;
 mod util;
 
@@ -25,13 +29,17 @@ use replyify::{Replyify, ResultExt};
 
 const MIN_GC_TICK: Duration = Duration::from_secs(60);
 
+/// Default interval between measurements on an SSE stream, when not
+/// overridden by the `interval` query parameter
+const DEFAULT_STREAM_INTERVAL: Duration = Duration::from_secs(1);
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    use clap::{Args, FromArgMatches};
+    use clap::Args;
 
     let matches = config::Config::augment_args_for_update(clap::command!())
         .arg(
-            clap::arg!(config: -c --config <FILE> "Read configuration from a TOML file")
+            clap::arg!(config: -c --config <FILE> "Read configuration from a TOML or JSON file")
                 .value_parser(clap::value_parser!(std::path::PathBuf)),
         )
         .arg(
@@ -40,27 +48,23 @@ async fn main() -> anyhow::Result<()> {
         )
         .get_matches();
 
-    let mut config = matches
+    let config_file = matches
         .get_one::<std::path::PathBuf>("config")
-        .map(config::Config::from_toml_file)
-        .transpose()
-        .context("Could not read config file")?
-        .unwrap_or_default();
-    config
-        .update_from_arg_matches(&matches)
-        .context("Could not extract configuration from CLI")?;
+        .map(std::path::PathBuf::as_path);
+    let config = config::Config::load(config_file, &matches)
+        .context("Could not assemble configuration")?;
+    let hot = Arc::new(ArcSwap::from_pointee(HotConfig::from(&config)));
     let config::Config {
         network,
-        oneshot,
-        gc,
         misc,
+        monitor,
+        hooks,
+        access,
+        persistence,
+        ..
     } = config;
 
     let base_uri = Arc::new(misc.base_uri);
-    let max_age: warp::http::header::HeaderValue =
-        format!("max-age={}", misc.cache_max_age.as_secs())
-            .try_into()
-            .context("Could not prepare a max-age directive")?;
 
     init_logger(LevelFilter::Warn, matches.get_count("verbosity").into())
         .context("Could not initialize logger")?;
@@ -79,14 +83,28 @@ async fn main() -> anyhow::Result<()> {
         std::future::ready(res)
     });
 
-    let campaigns = CAMPAIGNS.get_or_init(Default::default);
+    let restored = match persistence.file.as_deref() {
+        Some(path) => persistence::load(nvml, path).await.unwrap_or_else(|e| {
+            log::warn!("Could not load persisted campaign state: {e:#}");
+            Default::default()
+        }),
+        None => Default::default(),
+    };
+    let campaigns = CAMPAIGNS.get_or_init(|| sync::RwLock::new(restored));
     let campaign_param = warp::path::param().and_then(|i| get_campaign(campaigns, i));
     let campaigns_read = warp::any().then(|| campaigns.read());
     let campaigns_write = warp::any().then(|| campaigns.write());
 
     let oneshot_enabled = {
-        let enabled = oneshot.enable;
-        move || std::future::ready(enabled.then_some(()).ok_or_else(warp::reject::not_found))
+        let hot = hot.clone();
+        move || {
+            std::future::ready(
+                hot.load()
+                    .oneshot_enable
+                    .then_some(())
+                    .ok_or_else(warp::reject::not_found),
+            )
+        }
     };
 
     // End-point exposing the number of devices on this machine
@@ -95,18 +113,23 @@ async fn main() -> anyhow::Result<()> {
         .and(warp::path::end())
         .map(|| nvml.device_count().json_reply());
 
-    // End-points exposing various device info
+    // End-points exposing various device info; the non-ephemeral properties
+    // (name, UUID, serial) are cacheable for `misc.cache_max_age`, while the
+    // constantly-changing power usage is never cached
     let device_info = warp::get()
         .and(device)
         .and(warp::path::param())
         .and(warp::path::end())
-        .map(|d: nvml::Device, p: param::DeviceProperty| {
-            use param::DeviceProperty as DP;
-            match p {
-                DP::Name => d.name().json_reply(),
-                DP::Uuid => d.uuid().json_reply(),
-                DP::Serial => d.serial().json_reply(),
-                DP::PowerUsage => d.power_usage().json_reply(),
+        .map({
+            let hot = hot.clone();
+            move |d: nvml::Device, p: param::DeviceProperty| {
+                use param::DeviceProperty as DP;
+                match p {
+                    DP::Name => d.name().json_reply().cache_control(max_age_header(&hot)),
+                    DP::Uuid => d.uuid().json_reply().cache_control(max_age_header(&hot)),
+                    DP::Serial => d.serial().json_reply().cache_control(max_age_header(&hot)),
+                    DP::PowerUsage => d.power_usage().json_reply().no_cache(),
+                }
             }
         });
 
@@ -118,11 +141,13 @@ async fn main() -> anyhow::Result<()> {
         .untuple_one()
         .and(warp::path::end())
         .and(warp::query())
+        .and(warp::header::optional::<String>("accept"))
         .then({
-            let default_duration = oneshot.duration;
-            move |d: param::Duration| {
-                let duration = d.duration.unwrap_or(default_duration);
-                energy_oneshot(nvml, duration)
+            let hot = hot.clone();
+            move |d: param::Duration, accept: Option<String>| {
+                let duration = d.duration.unwrap_or_else(|| hot.load().oneshot_duration);
+                let media_type = encoding::negotiate(accept.as_deref());
+                energy_oneshot(nvml, duration, media_type)
             }
         });
 
@@ -132,9 +157,17 @@ async fn main() -> anyhow::Result<()> {
         .and(campaigns_write)
         .map({
             let base_uri = base_uri.clone();
+            let hook = hooks.campaign_created.clone();
             move |mut c: CampaignsWriteLock| {
                 let id = c.create(nvml).map_err(Replyify::replyify)?;
                 GC_NOTIFIER.notify_one();
+                hooks::fire(
+                    hook.as_deref(),
+                    hooks::Event::CampaignCreated {
+                        id,
+                        device_count: nvml.device_count().unwrap_or(0),
+                    },
+                );
 
                 format!("{base_uri}v1/energy/{id}")
                     .try_into()
@@ -149,13 +182,23 @@ async fn main() -> anyhow::Result<()> {
         .and(warp::path::param())
         .and(warp::path::end())
         .and(campaigns_write)
-        .map(|i, mut c: CampaignsWriteLock| {
-            use warp::http::StatusCode;
-
-            if c.delete(i).is_some() {
-                StatusCode::OK
-            } else {
-                StatusCode::NOT_FOUND
+        .map({
+            let hook = hooks.campaign_deleted.clone();
+            move |i, mut c: CampaignsWriteLock| {
+                use warp::http::StatusCode;
+
+                if c.delete(i).is_some() {
+                    hooks::fire(
+                        hook.as_deref(),
+                        hooks::Event::CampaignDeleted {
+                            id: i,
+                            device_count: nvml.device_count().unwrap_or(0),
+                        },
+                    );
+                    StatusCode::OK
+                } else {
+                    StatusCode::NOT_FOUND
+                }
             }
         });
 
@@ -163,12 +206,46 @@ async fn main() -> anyhow::Result<()> {
     let energy_measure = warp::get()
         .and(campaign_param)
         .and(warp::path::end())
-        .map(|b: CampaignReadLock| b.measurement().json_reply());
+        .and(warp::header::optional::<String>("accept"))
+        .map(|b: CampaignReadLock, accept: Option<String>| {
+            let media_type = encoding::negotiate(accept.as_deref());
+            match b.measurement() {
+                Ok(m) => encoding::encode(&m, media_type),
+                Err(e) => Replyify::replyify(e).into_response(),
+            }
+        });
+
+    // End-point for streaming measurements of a freshly-created campaign via SSE
+    let energy_stream_new = warp::get()
+        .and(warp::path("stream"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(move |q: param::Interval| {
+            let interval = q.interval.unwrap_or(DEFAULT_STREAM_INTERVAL);
+            match energy::BaseMeasurement::new(nvml) {
+                Ok(base) => warp::sse::reply(warp::sse::keep_alive().stream(new_campaign_stream(interval, base)))
+                    .into_response(),
+                Err(e) => Replyify::replyify(e).into_response(),
+            }
+        });
+
+    // End-point for streaming measurements of an existing campaign via SSE
+    let energy_stream_campaign = warp::get()
+        .and(warp::path::param())
+        .and(warp::path("stream"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .map(move |id: energy::BMId, q: param::Interval| {
+            let interval = q.interval.unwrap_or(DEFAULT_STREAM_INTERVAL);
+            warp::sse::reply(warp::sse::keep_alive().stream(campaign_stream(campaigns, id, interval)))
+        });
 
     let energy = energy_oneshot
         .or(energy_create)
         .or(energy_delete)
-        .or(energy_measure);
+        .or(energy_measure)
+        .or(energy_stream_new)
+        .or(energy_stream_campaign);
     let energy = warp::path("energy").and(energy);
 
     // Ping end-point
@@ -187,15 +264,214 @@ async fn main() -> anyhow::Result<()> {
     let v1_api = device_count.or(device).or(energy).or(ping).or(health);
     let v1_api = warp::path("v1").and(v1_api).with(warp::log("traffic"));
 
-    let incoming = incoming_from(network.listen_addrs())
+    // End-point exposing the background sampler's gauges in OpenMetrics format
+    let sampler = METRICS.get_or_init(Default::default);
+    let metrics_enabled = {
+        let enabled = monitor.enable;
+        move || std::future::ready(enabled.then_some(()).ok_or_else(warp::reject::not_found))
+    };
+    let metrics_route = warp::get()
+        .and_then(metrics_enabled)
+        .untuple_one()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .then(move || async move {
+            sampler.render(nvml).await.replyify().with_header(
+                warp::http::header::CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )
+        })
+        .with(warp::log("traffic"));
+
+    let routes = access_control(access.allowed, access.trusted_proxies)
+        .and(v1_api.or(metrics_route))
+        .recover(handle_rejection);
+
+    // One `warp` server per configured listen address, each bound the
+    // normal (address-based) way so `warp::addr::remote()` in
+    // `access_control` actually gets a peer address to report, and each with
+    // its own graceful-shutdown signal (installing a signal handler more
+    // than once is fine; all of them fire together).
+    let serve = futures_util::future::join_all(network.listen_addrs().map(|addr| {
+        log::trace!("Binding to address {addr}");
+        warp::serve(routes.clone())
+            .bind_with_graceful_shutdown(addr, shutdown_signal())
+            .1
+    }));
+
+    let gc = collect_garbage(campaigns, &hot, hooks.campaigns_reaped.as_deref());
+    let monitor = sampler.run(
+        nvml,
+        monitor.period,
+        monitor.enable,
+        hooks.power_threshold_mw,
+        hooks.threshold_crossed.as_deref(),
+    );
+    let reload = reload_on_sighup(&hot, config_file, &matches);
+    let persist = persistence::run(campaigns, persistence.file.as_deref(), persistence.period);
+
+    // `serve` resolves once the shutdown signal fired and all in-flight
+    // requests (including outstanding oneshot measurements) have
+    // completed; `gc`, `monitor`, `reload` and `persist` run forever and
+    // are dropped once that happens.
+    tokio::select! {
+        _ = serve => {},
+        _ = gc => {},
+        _ = monitor => {},
+        _ = reload => {},
+        _ = persist => {},
+    }
+
+    log::info!("Shutting down, running a final garbage collection pass");
+    let reaped = campaigns
+        .write()
         .await
-        .context("Could not start up server")?;
-    let serve = warp::serve(v1_api).run_incoming(incoming);
+        .delete_older_than(std::time::Instant::now() - hot.load().gc_min_age);
+    if reaped > 0 {
+        hooks::fire(
+            hooks.campaigns_reaped.as_deref(),
+            hooks::Event::CampaignsReaped { count: reaped },
+        );
+    }
 
-    let gc = collect_garbage(campaigns, gc.min_age, gc.min_campaigns);
+    if let Some(path) = persistence.file.as_deref() {
+        if let Err(e) = persistence::save(campaigns, path).await {
+            log::warn!("Could not persist campaign state during shutdown: {e:#}");
+        }
+    }
 
-    tokio::join!(serve, gc);
-    unreachable!()
+    Ok(())
+}
+
+/// Resolves once a `SIGINT` (or, on unix, `SIGTERM`) is received
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not install a SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        signal(SignalKind::terminate())
+            .expect("Could not install a SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    log::info!("Received shutdown signal, draining in-flight requests");
+}
+
+/// The subset of [config::Config] that can be live-reloaded via `SIGHUP`
+/// without a restart
+struct HotConfig {
+    gc_min_age: Duration,
+    gc_min_campaigns: NonZeroUsize,
+    oneshot_duration: Duration,
+    oneshot_enable: bool,
+    cache_max_age: Duration,
+}
+
+impl From<&config::Config> for HotConfig {
+    fn from(config: &config::Config) -> Self {
+        Self {
+            gc_min_age: config.gc.min_age,
+            gc_min_campaigns: config.gc.min_campaigns,
+            oneshot_duration: config.oneshot.duration,
+            oneshot_enable: config.oneshot.enable,
+            cache_max_age: config.misc.cache_max_age,
+        }
+    }
+}
+
+/// On unix, re-read the configuration file on every `SIGHUP` and atomically
+/// swap the reloadable parameters in `hot`, keeping the current values (and
+/// logging a warning) if the file fails to parse. Never resolves on
+/// platforms without `SIGHUP`.
+async fn reload_on_sighup(
+    hot: &ArcSwap<HotConfig>,
+    config_file: Option<&std::path::Path>,
+    matches: &clap::ArgMatches,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup =
+            signal(SignalKind::hangup()).expect("Could not install a SIGHUP handler");
+        loop {
+            sighup.recv().await;
+            log::info!("Received SIGHUP, reloading configuration");
+            match config::Config::load(config_file, matches) {
+                Ok(config) => hot.store(Arc::new(HotConfig::from(&config))),
+                Err(e) => log::warn!("Could not reload configuration, keeping current values: {e:#}"),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await
+}
+
+/// Turn a [util::AccessDenied] rejection into a `403`, leaving every other
+/// rejection to warp's default handling
+async fn handle_rejection(
+    err: warp::Rejection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if err.find::<util::AccessDenied>().is_some() {
+        Ok(warp::reply::with_status(
+            "Access denied",
+            warp::http::StatusCode::FORBIDDEN,
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+/// Build a `max-age=...` `Cache-control` directive from the current,
+/// hot-reloadable `cache_max_age`
+fn max_age_header(hot: &ArcSwap<HotConfig>) -> warp::http::header::HeaderValue {
+    format!("max-age={}", hot.load().cache_max_age.as_secs())
+        .try_into()
+        .expect("max-age directive built from a duration should always be a valid header value")
+}
+
+/// Filter enforcing the configured IP allowlist, resolving the effective
+/// client address through `trusted_proxies` and `X-Forwarded-For` first; an
+/// empty `allowed` (the default) admits every client unconditionally. Relies
+/// on `warp::addr::remote()`, which only reports a peer address when the
+/// server was bound the normal, address-based way.
+fn access_control(
+    allowed: Vec<config::CidrBlock>,
+    trusted_proxies: Vec<net::IpAddr>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(move |peer: Option<net::SocketAddr>, forwarded: Option<String>| {
+            let allowed = allowed.clone();
+            let trusted_proxies = trusted_proxies.clone();
+            async move {
+                if allowed.is_empty() {
+                    return Ok(());
+                }
+                let client = peer.and_then(|p| {
+                    util::resolve_client_ip(p.ip(), forwarded.as_deref(), &trusted_proxies)
+                });
+                match client {
+                    Some(ip) if allowed.iter().any(|block| block.contains(ip)) => Ok(()),
+                    _ => Err(warp::reject::custom(util::AccessDenied)),
+                }
+            }
+        })
+        .untuple_one()
 }
 
 /// NVML instance
@@ -216,40 +492,74 @@ fn init_logger(level: LevelFilter, modifier: usize) -> Result<(), impl std::erro
     logger.with_level(level).init()
 }
 
-/// Create a stream of incoming TCP connections from a addresses to bind to
-async fn incoming_from(
-    addrs: impl IntoIterator<Item = net::SocketAddr>,
-) -> anyhow::Result<impl futures_util::TryStream<Ok = TcpStream, Error = std::io::Error>> {
-    use futures_util::stream::{self, StreamExt};
-
-    let mut incoming = stream::SelectAll::new();
-    for addr in addrs {
-        log::trace!("Binding to address {addr}");
-        let listener = TcpListener::bind(addr)
-            .await
-            .context("Could not bind to address '{addr}'")?;
-        let listener = Arc::new(listener);
-        let tcp_streams = stream::repeat(()).then(move |_| do_accept(listener.clone()));
-        incoming.push(Box::pin(tcp_streams));
-    }
-    Ok(incoming)
-}
-
-/// Accept a connection from a given listener
-async fn do_accept(listener: Arc<TcpListener>) -> Result<TcpStream, std::io::Error> {
-    listener.accept().await.map(|(s, _)| s)
-}
-
 /// Perform a "blocking" oneshot measurement over a given duration
 async fn energy_oneshot(
     nvml: &'static nvml::Nvml,
     duration: Duration,
-) -> Result<impl warp::Reply, impl warp::Reply> {
-    let base = energy::BaseMeasurement::new(nvml).map_err(Replyify::replyify)?;
+    media_type: Option<encoding::MediaType>,
+) -> warp::reply::Response {
+    let base = match energy::BaseMeasurement::new(nvml) {
+        Ok(base) => base,
+        Err(e) => return Replyify::replyify(e).into_response(),
+    };
 
     tokio::time::sleep(duration).await;
 
-    base.measurement().json_reply()
+    match base.measurement() {
+        Ok(m) => encoding::encode(&m, media_type),
+        Err(e) => Replyify::replyify(e).into_response(),
+    }
+}
+
+/// SSE stream of periodic delta measurements against a freshly-created,
+/// unshared [energy::BaseMeasurement]
+fn new_campaign_stream(
+    interval: Duration,
+    base: energy::BaseMeasurement,
+) -> impl futures_util::Stream<Item = Result<warp::sse::Event, std::convert::Infallible>> {
+    use futures_util::stream;
+
+    let timer = tokio::time::interval(interval);
+    stream::unfold((timer, base), |(mut timer, base)| async move {
+        timer.tick().await;
+        match base.measurement() {
+            Ok(m) => Some((Ok(sse_event(&m)), (timer, base))),
+            Err(e) => {
+                log::warn!("Could not compute streamed measurement: {e:#}");
+                None
+            }
+        }
+    })
+}
+
+/// SSE stream of periodic delta measurements of the campaign `id`, re-read
+/// from `campaigns` on every tick; ends once the campaign is gone
+fn campaign_stream(
+    campaigns: &'static Campaigns,
+    id: energy::BMId,
+    interval: Duration,
+) -> impl futures_util::Stream<Item = Result<warp::sse::Event, std::convert::Infallible>> {
+    let timer = tokio::time::interval(interval);
+    futures_util::stream::unfold(timer, move |mut timer| async move {
+        timer.tick().await;
+        match campaigns.read().await.get(id)?.measurement() {
+            Ok(m) => Some((Ok(sse_event(&m)), timer)),
+            Err(e) => {
+                log::warn!("Could not compute streamed measurement: {e:#}");
+                None
+            }
+        }
+    })
+}
+
+/// Render a [energy::Measurement] as a JSON SSE [warp::sse::Event]
+fn sse_event(measurement: &energy::Measurement) -> warp::sse::Event {
+    warp::sse::Event::default()
+        .json_data(measurement)
+        .unwrap_or_else(|e| {
+            log::warn!("Could not encode streamed measurement as an SSE event: {e}");
+            warp::sse::Event::default()
+        })
 }
 
 type Campaigns = sync::RwLock<BaseMeasurements>;
@@ -271,9 +581,20 @@ async fn get_campaign(
 
 static CAMPAIGNS: OnceLock<Campaigns> = OnceLock::new();
 
+/// Background energy/power sampler backing the `/metrics` end-point
+static METRICS: OnceLock<metrics::Sampler> = OnceLock::new();
+
 /// Runs cyclic garbage collection after being notified
-async fn collect_garbage(campaigns: &Campaigns, min_age: Duration, min_campaigns: NonZeroUsize) {
-    let tick_duration = std::cmp::max(min_age / 4, MIN_GC_TICK);
+///
+/// `min_age` and `min_campaigns` are re-read from `hot` on every pass, so a
+/// `SIGHUP` reload takes effect without restarting this loop; the tick
+/// cadence itself is derived once, from the `min_age` in effect at startup.
+async fn collect_garbage(
+    campaigns: &Campaigns,
+    hot: &ArcSwap<HotConfig>,
+    reaped_hook: Option<&std::path::Path>,
+) {
+    let tick_duration = std::cmp::max(hot.load().gc_min_age / 4, MIN_GC_TICK);
 
     let mut timer = tokio::time::interval(tick_duration);
     timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
@@ -285,6 +606,10 @@ async fn collect_garbage(campaigns: &Campaigns, min_age: Duration, min_campaigns
 
         log::trace!("Triggering garbage collection");
 
+        let current = hot.load();
+        let min_age = current.gc_min_age;
+        let min_campaigns = current.gc_min_campaigns;
+
         // We definitely only want to hold this lock for a short time.
         let mut campaigns = campaigns.write().await;
 
@@ -295,9 +620,57 @@ async fn collect_garbage(campaigns: &Campaigns, min_age: Duration, min_campaigns
         // campaigns.
         if count >= min_campaigns.get() {
             log::info!("Performing garbage collection");
-            campaigns.delete_older_than(now - min_age);
+            let reaped = campaigns.delete_older_than(now - min_age);
+            if reaped > 0 {
+                hooks::fire(reaped_hook, hooks::Event::CampaignsReaped { count: reaped });
+            }
         }
     }
 }
 
 static GC_NOTIFIER: sync::Notify = sync::Notify::const_new();
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    /// Build the full filter chain `access_control` normally guards, down
+    /// to a trivial `200 OK` handler, the way it's wired up in `main`
+    fn test_routes(
+        allowed: Vec<config::CidrBlock>,
+    ) -> impl Filter<Extract = impl Reply, Error = std::convert::Infallible> + Clone {
+        access_control(allowed, Vec::new())
+            .and(warp::any().map(warp::reply))
+            .recover(handle_rejection)
+    }
+
+    #[tokio::test]
+    async fn access_control_admits_an_allowed_peer() {
+        let allowed: Vec<config::CidrBlock> =
+            vec!["127.0.0.1".parse().expect("Could not parse CIDR block")];
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12345);
+
+        let resp = warp::test::request()
+            .remote_addr(peer)
+            .reply(&test_routes(allowed))
+            .await;
+
+        assert_eq!(resp.status(), warp::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn access_control_denies_a_peer_outside_the_allowlist() {
+        let allowed: Vec<config::CidrBlock> =
+            vec!["10.0.0.0/8".parse().expect("Could not parse CIDR block")];
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12345);
+
+        let resp = warp::test::request()
+            .remote_addr(peer)
+            .reply(&test_routes(allowed))
+            .await;
+
+        assert_eq!(resp.status(), warp::http::StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,251 @@
+//! Background energy/power sampling and OpenMetrics rendering for the
+//! `/metrics` end-point
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use nvml_wrapper as nvml;
+use tokio::sync::RwLock;
+
+/// Background sampler, holding the last-seen gauges for every device
+#[derive(Default, Debug)]
+pub struct Sampler {
+    devices: RwLock<HashMap<u32, DeviceGauges>>,
+}
+
+/// Cumulative energy and last-interval power for a single device
+#[derive(Debug)]
+struct DeviceGauges {
+    name: String,
+    uuid: String,
+    serial: String,
+    energy_mj: u64,
+    power_mw: Option<u64>,
+    time: Instant,
+    /// Whether `power_mw` was at or above the configured threshold as of
+    /// the previous sample, so [Sampler::sample] can fire
+    /// [crate::hooks::Event::ThresholdCrossed] only on the `false -> true`
+    /// edge instead of on every tick spent above it
+    above_threshold: bool,
+}
+
+impl Sampler {
+    /// Run the sampler forever, taking a new sample every `period`; if
+    /// `enabled` is `false`, this never resolves and never samples,
+    /// mirroring how the oneshot end-points are gated by `Oneshot::enable`.
+    /// When a device's last-interval power crosses `power_threshold_mw`
+    /// from below, `threshold_hook` is fired once with a
+    /// [crate::hooks::Event::ThresholdCrossed]; it fires again only after
+    /// the power has dropped back below the threshold and crosses it again.
+    pub async fn run(
+        &self,
+        nvml: &nvml::Nvml,
+        period: Duration,
+        enabled: bool,
+        power_threshold_mw: Option<u64>,
+        threshold_hook: Option<&std::path::Path>,
+    ) {
+        if !enabled {
+            return std::future::pending().await;
+        }
+
+        let mut timer = tokio::time::interval(period);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+            if let Err(e) = self.sample(nvml, power_threshold_mw, threshold_hook).await {
+                log::warn!("Could not take a monitoring sample: {e:#}");
+            }
+        }
+    }
+
+    /// Take a single sample of every device's cumulative energy consumption
+    async fn sample(
+        &self,
+        nvml: &nvml::Nvml,
+        power_threshold_mw: Option<u64>,
+        threshold_hook: Option<&std::path::Path>,
+    ) -> Result<()> {
+        use std::collections::hash_map::Entry;
+
+        let device_count = nvml
+            .device_count()
+            .context("Could not retrieve device count")?;
+        let now = Instant::now();
+
+        let mut devices = self.devices.write().await;
+        for i in 0..device_count {
+            let device = nvml
+                .device_by_index(i)
+                .with_context(|| format!("Could not retrieve device {i}"))?;
+            let energy_mj = device
+                .total_energy_consumption()
+                .with_context(|| format!("Could not retrieve total energy consumption of device {i}"))?;
+
+            match devices.entry(i) {
+                Entry::Occupied(mut e) => {
+                    let g = e.get_mut();
+                    let dt = now.duration_since(g.time);
+                    g.power_mw = energy_mj.checked_sub(g.energy_mj).and_then(|delta_mj| {
+                        let micros = dt.as_micros();
+                        (micros > 0).then(|| (delta_mj as u128 * 1_000_000 / micros) as u64)
+                    });
+                    g.energy_mj = energy_mj;
+                    g.time = now;
+
+                    if crossed_threshold(g.above_threshold, g.power_mw, power_threshold_mw) {
+                        crate::hooks::fire(
+                            threshold_hook,
+                            crate::hooks::Event::ThresholdCrossed {
+                                device: i,
+                                // reachable only once `power_mw` is `Some`
+                                value_mw: g.power_mw.unwrap_or_default(),
+                            },
+                        );
+                    }
+                    g.above_threshold = is_above_threshold(g.power_mw, power_threshold_mw);
+                }
+                Entry::Vacant(v) => {
+                    v.insert(DeviceGauges {
+                        name: device.name().unwrap_or_default(),
+                        uuid: device.uuid().unwrap_or_default(),
+                        serial: device.serial().unwrap_or_default(),
+                        energy_mj,
+                        power_mw: None,
+                        time: now,
+                        above_threshold: false,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the currently-held gauges, plus driver/NVML version as an
+    /// `info` metric mirroring [crate::health::Health], as OpenMetrics text
+    pub async fn render(&self, nvml: &nvml::Nvml) -> Result<String> {
+        let driver_version = nvml
+            .sys_driver_version()
+            .context("Could not retrieve driver version")?;
+        let nvml_version = nvml
+            .sys_nvml_version()
+            .context("Could not retrieve NVML version")?;
+
+        let mut out = String::new();
+        writeln!(out, "# TYPE mongeu info")?;
+        writeln!(
+            out,
+            "mongeu_info{{version=\"{}\",driver_version=\"{driver_version}\",nvml_version=\"{nvml_version}\"}} 1",
+            env!("CARGO_PKG_VERSION")
+        )?;
+
+        let devices = self.devices.read().await;
+        let mut ids: Vec<_> = devices.keys().copied().collect();
+        ids.sort_unstable();
+        let labels = |id: u32, g: &DeviceGauges| {
+            format!(
+                "device=\"{id}\",name=\"{}\",uuid=\"{}\",serial=\"{}\"",
+                escape_label_value(&g.name),
+                escape_label_value(&g.uuid),
+                escape_label_value(&g.serial)
+            )
+        };
+
+        writeln!(out, "# TYPE mongeu_device_energy_millijoules gauge")?;
+        writeln!(out, "# UNIT mongeu_device_energy_millijoules millijoules")?;
+        for &id in &ids {
+            let g = &devices[&id];
+            writeln!(
+                out,
+                "mongeu_device_energy_millijoules{{{}}} {}",
+                labels(id, g),
+                g.energy_mj
+            )?;
+        }
+
+        writeln!(out, "# TYPE mongeu_device_power_milliwatts gauge")?;
+        writeln!(out, "# UNIT mongeu_device_power_milliwatts milliwatts")?;
+        for &id in &ids {
+            let g = &devices[&id];
+            if let Some(power_mw) = g.power_mw {
+                writeln!(out, "mongeu_device_power_milliwatts{{{}}} {power_mw}", labels(id, g))?;
+            }
+        }
+        writeln!(out, "# EOF")?;
+
+        Ok(out)
+    }
+}
+
+/// Whether `power_mw` is at or above `threshold_mw`; `false` whenever
+/// either is absent (no reading yet, or thresholding disabled)
+fn is_above_threshold(power_mw: Option<u64>, threshold_mw: Option<u64>) -> bool {
+    matches!((power_mw, threshold_mw), (Some(power_mw), Some(threshold_mw)) if power_mw >= threshold_mw)
+}
+
+/// Whether a device just crossed `threshold_mw` from below, i.e. it was not
+/// above it as of the previous sample (`was_above`) but its current
+/// `power_mw` now is
+fn crossed_threshold(was_above: bool, power_mw: Option<u64>, threshold_mw: Option<u64>) -> bool {
+    !was_above && is_above_threshold(power_mw, threshold_mw)
+}
+
+/// Escape a string for use as an OpenMetrics label value: backslashes,
+/// double quotes and newlines must be escaped, since driver-reported
+/// strings (device name, UUID, serial) are not guaranteed to be free of them
+fn escape_label_value(value: &str) -> std::borrow::Cow<'_, str> {
+    if !value.contains(['\\', '"', '\n']) {
+        return std::borrow::Cow::Borrowed(value);
+    }
+
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    std::borrow::Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossed_threshold_fires_only_on_the_rising_edge() {
+        assert!(crossed_threshold(false, Some(100), Some(100)));
+        assert!(!crossed_threshold(true, Some(100), Some(100)));
+    }
+
+    #[test]
+    fn crossed_threshold_does_not_fire_while_below() {
+        assert!(!crossed_threshold(false, Some(50), Some(100)));
+        assert!(!crossed_threshold(true, Some(50), Some(100)));
+    }
+
+    #[test]
+    fn crossed_threshold_ignores_missing_readings_or_threshold() {
+        assert!(!crossed_threshold(false, None, Some(100)));
+        assert!(!crossed_threshold(false, Some(100), None));
+    }
+
+    #[test]
+    fn escape_label_value_passes_through_plain_strings_unchanged() {
+        assert!(matches!(
+            escape_label_value("NVIDIA GeForce RTX 4090"),
+            std::borrow::Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn escape_label_value_escapes_quotes_backslashes_and_newlines() {
+        assert_eq!(
+            escape_label_value("a \"quoted\"\\name\nwith a newline"),
+            "a \\\"quoted\\\"\\\\name\\nwith a newline"
+        );
+    }
+}
@@ -11,6 +11,14 @@ pub struct Duration {
     pub duration: Option<std::time::Duration>,
 }
 
+/// Helper type for representing a sampling interval in `ms` in a parameter
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Interval {
+    #[serde(deserialize_with = "util::deserialize_opt_millis")]
+    #[serde(default)]
+    pub interval: Option<std::time::Duration>,
+}
+
 /// Helper type for handling names of device properties
 #[derive(Copy, Clone, Debug)]
 pub enum DeviceProperty {
@@ -0,0 +1,123 @@
+//! Persisting active measurement campaigns across restarts
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use nvml_wrapper as nvml;
+use tokio::sync::RwLock;
+
+use crate::energy::{BMId, BaseMeasurement, BaseMeasurements, PersistedMeasurement};
+
+/// On-disk representation of the set of active campaigns
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct PersistedState {
+    campaigns: HashMap<BMId, PersistedMeasurement>,
+}
+
+/// Persist the current set of `campaigns` to `path`
+pub async fn save(campaigns: &RwLock<BaseMeasurements>, path: &Path) -> Result<()> {
+    let state = {
+        let campaigns = campaigns.read().await;
+        PersistedState {
+            campaigns: campaigns.iter().map(|(id, b)| (id, b.persist())).collect(),
+        }
+    };
+    let toml = toml::to_string(&state).context("Could not serialize campaign state")?;
+    tokio::fs::write(path, toml)
+        .await
+        .with_context(|| format!("Could not write campaign state to {}", path.display()))
+}
+
+/// Restore campaigns previously [save]d at `path`. A missing file restores
+/// an empty store; any campaign that fails to restore (e.g. because of a
+/// detected driver reset) is skipped with a warning rather than aborting
+/// the whole restore.
+pub async fn load(nvml: &'static nvml::Nvml, path: &Path) -> Result<BaseMeasurements> {
+    let toml = match tokio::fs::read_to_string(path).await {
+        Ok(toml) => toml,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Could not read campaign state from {}", path.display()))
+        }
+    };
+    let state: PersistedState = toml::from_str(&toml).context("Could not parse campaign state")?;
+
+    let mut campaigns = BaseMeasurements::default();
+    for (id, persisted) in state.campaigns {
+        match BaseMeasurement::restore(nvml, &persisted) {
+            Ok(Some(measurement)) => campaigns.restore(id, measurement),
+            Ok(None) => log::warn!("Skipped restoring campaign {id}, its baseline predates a driver reset"),
+            Err(e) => log::warn!("Could not restore campaign {id}: {e:#}"),
+        }
+    }
+    Ok(campaigns)
+}
+
+/// Periodically persist `campaigns` to `path`. Never resolves; runs
+/// forever if `path` is set, or does nothing (persistence disabled) if not.
+pub async fn run(campaigns: &RwLock<BaseMeasurements>, path: Option<&Path>, period: Duration) {
+    let Some(path) = path else {
+        return std::future::pending().await;
+    };
+
+    let mut timer = tokio::time::interval(period);
+    loop {
+        timer.tick().await;
+        log::trace!("Persisting campaign state");
+        if let Err(e) = save(campaigns, path).await {
+            log::warn!("Could not persist campaign state: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Acquire a `'static` NVML handle for a test, or skip it if this
+    /// environment has no NVML-capable GPU to exercise `BaseMeasurement`
+    /// against
+    macro_rules! nvml_or_skip {
+        () => {
+            match nvml::Nvml::init() {
+                Ok(nvml) => &*Box::leak(Box::new(nvml)),
+                Err(e) => {
+                    eprintln!("Skipping test, could not initialize NVML: {e}");
+                    return;
+                }
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn load_of_a_missing_file_restores_an_empty_default() {
+        let nvml = nvml_or_skip!();
+        let dir = tempfile::tempdir().expect("Could not create a temp dir");
+        let path = dir.path().join("does-not-exist.toml");
+
+        let campaigns = load(nvml, &path).await.expect("Could not load campaign state");
+
+        assert_eq!(campaigns.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn save_then_load_restores_a_campaign() {
+        let nvml = nvml_or_skip!();
+        let dir = tempfile::tempdir().expect("Could not create a temp dir");
+        let path = dir.path().join("campaigns.toml");
+
+        let campaigns = RwLock::new(BaseMeasurements::default());
+        let id = campaigns
+            .write()
+            .await
+            .create(nvml)
+            .expect("Could not create a campaign");
+
+        save(&campaigns, &path).await.expect("Could not save campaign state");
+
+        let restored = load(nvml, &path).await.expect("Could not load campaign state");
+        assert!(restored.get(id).is_some());
+    }
+}
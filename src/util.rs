@@ -1,4 +1,5 @@
 //! Utilities
+use std::net::IpAddr;
 use std::num::{NonZeroU64, ParseIntError};
 use std::time::Duration;
 
@@ -76,3 +77,90 @@ fn sanitize_base_uri(uri: Uri) -> anyhow::Result<Uri> {
 pub struct DeviceRetrievalError(pub u32);
 
 impl warp::reject::Reject for DeviceRetrievalError {}
+
+/// Rejection for a client that is not covered by the configured IP allowlist
+#[derive(Debug)]
+pub struct AccessDenied;
+
+impl warp::reject::Reject for AccessDenied {}
+
+/// Resolve the effective client address: the `peer` address itself, unless
+/// it is one of `trusted_proxies`, in which case the right-most untrusted
+/// entry of `forwarded_for` (an `X-Forwarded-For` header value) is used
+/// instead. Returns `None` (denying the request) if `peer` is trusted but
+/// `forwarded_for` is absent or malformed.
+pub fn resolve_client_ip(
+    peer: IpAddr,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    forwarded_for.and_then(|header| rightmost_untrusted_forwarded(header, trusted_proxies))
+}
+
+/// Parse a comma-separated `X-Forwarded-For` header value, returning the
+/// right-most entry not in `trusted_proxies`. Tolerates surrounding
+/// whitespace and bracketed IPv6 literals (`[::1]`); any unparseable entry
+/// invalidates the whole header, erring towards denial (`None`) rather
+/// than guessing at the client's address.
+fn rightmost_untrusted_forwarded(header: &str, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let entries = header
+        .split(',')
+        .map(|entry| {
+            let entry = entry.trim();
+            let entry = entry
+                .strip_prefix('[')
+                .and_then(|e| e.strip_suffix(']'))
+                .unwrap_or(entry);
+            entry.parse::<IpAddr>().ok()
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    entries.into_iter().rev().find(|ip| !trusted_proxies.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_client_ip_uses_peer_when_untrusted() {
+        let peer = IpAddr::from([203, 0, 113, 1]);
+        assert_eq!(resolve_client_ip(peer, Some("198.51.100.1"), &[]), Some(peer));
+    }
+
+    #[test]
+    fn resolve_client_ip_honors_forwarded_for_from_trusted_proxy() {
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        let client = IpAddr::from([198, 51, 100, 1]);
+        let header = format!("{client}, {peer}");
+        assert_eq!(
+            resolve_client_ip(peer, Some(&header), &[peer]),
+            Some(client)
+        );
+    }
+
+    #[test]
+    fn resolve_client_ip_denies_trusted_peer_without_header() {
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(resolve_client_ip(peer, None, &[peer]), None);
+    }
+
+    #[test]
+    fn resolve_client_ip_denies_on_malformed_header() {
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(resolve_client_ip(peer, Some("not an ip"), &[peer]), None);
+    }
+
+    #[test]
+    fn rightmost_untrusted_forwarded_tolerates_whitespace_and_brackets() {
+        let trusted = [IpAddr::from([10, 0, 0, 1])];
+        let header = "  [2001:db8::1]  ,  10.0.0.1  ";
+        assert_eq!(
+            rightmost_untrusted_forwarded(header, &trusted),
+            Some(IpAddr::from([0x2001, 0x0db8, 0, 0, 0, 0, 0, 1]))
+        );
+    }
+}